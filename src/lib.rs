@@ -0,0 +1,666 @@
+use async_compression::tokio::bufread::GzipEncoder;
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use reqwest::Body;
+use serde::Serialize;
+use std::future::Future;
+use std::io::{self, Write};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWriteExt, BufReader, DuplexStream, ReadBuf};
+use tokio::runtime::Handle;
+use tokio::sync::{oneshot, Notify};
+use tokio_util::io::ReaderStream;
+
+// default capacity of the duplex channel between the serializer and the HTTP body stream, in
+// bytes; overridable via `JsonStreamBuilder::high_water_mark`
+const DEFAULT_HIGH_WATER_MARK: usize = 64 * 1024;
+
+// Tracks how many serialized-but-unsent bytes are sitting in the duplex channel and implements
+// the high/low-water-mark hysteresis: once `high` bytes are in flight, the producer suspends
+// until the consumer has drained it back down to `low`, rather than resuming the instant a single
+// byte is freed.
+struct Watermark {
+    in_flight: AtomicUsize,
+    high: usize,
+    low: usize,
+    drained: Notify,
+}
+
+impl Watermark {
+    fn new(high: usize, low: usize) -> Self {
+        Self {
+            in_flight: AtomicUsize::new(0),
+            high,
+            low,
+            drained: Notify::new(),
+        }
+    }
+
+    fn produced(&self, n: usize) {
+        self.in_flight.fetch_add(n, Ordering::SeqCst);
+    }
+
+    fn consumed(&self, n: usize) {
+        let remaining = self.in_flight.fetch_sub(n, Ordering::SeqCst) - n;
+        if remaining <= self.low {
+            self.drained.notify_waiters();
+        }
+    }
+
+    fn over_high(&self) -> bool {
+        self.in_flight.load(Ordering::SeqCst) >= self.high
+    }
+
+    // waits until the consumer has drained in-flight bytes back down to `low`; uses the
+    // check-register-check dance Notify requires to avoid missing a wakeup that lands between
+    // the two checks
+    async fn wait_until_drained(&self) {
+        loop {
+            if self.in_flight.load(Ordering::SeqCst) <= self.low {
+                return;
+            }
+            let notified = self.drained.notified();
+            if self.in_flight.load(Ordering::SeqCst) <= self.low {
+                return;
+            }
+            notified.await;
+        }
+    }
+}
+
+// `tokio::io::DuplexStream`'s write half wrapped as a blocking `std::io::Write` so that
+// `serde_json::to_writer`, which only knows how to write synchronously, can push bytes into it.
+// Each `write` suspends on the duplex channel itself (via `Handle::block_on`) whenever its buffer
+// is full, and additionally, once `watermark` reports `high` bytes in flight, suspends until the
+// consumer has drained back down to `low` -- real hysteresis instead of unblocking the instant a
+// single byte is freed. This must run on a blocking thread (e.g. `tokio::task::spawn_blocking`),
+// never on an async worker.
+//
+// If the read half has been dropped (the consumer is gone, e.g. a mid-upload disconnect), the
+// duplex channel reports that back as a `BrokenPipe` error from `write`/`flush`, which unwinds the
+// producer instead of blocking on it forever.
+struct DuplexWriter {
+    inner: DuplexStream,
+    handle: Handle,
+    watermark: Arc<Watermark>,
+}
+
+impl DuplexWriter {
+    fn new(inner: DuplexStream, handle: Handle, watermark: Arc<Watermark>) -> Self {
+        Self {
+            inner,
+            handle,
+            watermark,
+        }
+    }
+}
+
+impl Write for DuplexWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.handle.block_on(self.inner.write(buf))?;
+        self.watermark.produced(n);
+        if self.watermark.over_high() {
+            self.handle.block_on(self.watermark.wait_until_drained());
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.handle.block_on(self.inner.flush())
+    }
+}
+
+// Wraps an `AsyncRead` and reports every byte that leaves it back to the shared `Watermark`, so
+// the producer's hysteresis check reflects bytes actually freed from the duplex channel -- this
+// must sit directly on the duplex read half, upstream of any further transform like gzip, since
+// it's duplex capacity we're tracking, not the final encoded size.
+struct WatermarkReader<R> {
+    inner: R,
+    watermark: Arc<Watermark>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for WatermarkReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if res.is_ready() {
+            let read = buf.filled().len() - before;
+            if read > 0 {
+                self.watermark.consumed(read);
+            }
+        }
+        res
+    }
+}
+
+// Once the consumer is gone nothing will ever drain the watermark again, so a producer parked in
+// `wait_until_drained` is released here; its next write then hits the closed duplex channel and
+// fails with `BrokenPipe`.
+impl<R> Drop for WatermarkReader<R> {
+    fn drop(&mut self) {
+        self.watermark.in_flight.store(0, Ordering::SeqCst);
+        self.watermark.drained.notify_waiters();
+    }
+}
+
+/// Output shape of a streamed JSON body.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// A single JSON array: `[item,item,...]`.
+    Array,
+    /// Newline-delimited JSON (NDJSON / JSON Lines): one complete value per line, with no
+    /// enclosing brackets or separators, e.g. `{...}\n{...}\n`. More robust than a giant array
+    /// when the server parses incrementally or the connection drops mid-stream.
+    JsonLines,
+}
+
+impl Format {
+    /// The `Content-Type` header value conventionally used for this format.
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Array => "application/json",
+            Format::JsonLines => "application/x-ndjson",
+        }
+    }
+}
+
+/// Builds a streaming JSON `Body`, configuring the output shape and the amount of
+/// serialized-but-unsent data allowed to accumulate before the producer blocks.
+pub struct JsonStreamBuilder {
+    format: Format,
+    field: Option<&'static str>,
+    high_water_mark: usize,
+    low_water_mark: Option<usize>,
+    gzip: bool,
+}
+
+impl JsonStreamBuilder {
+    /// Starts from `Format::Array`, no object field, a 64 KiB high water mark (and a low water
+    /// mark of half that), and no compression.
+    pub fn new() -> Self {
+        Self {
+            format: Format::Array,
+            field: None,
+            high_water_mark: DEFAULT_HIGH_WATER_MARK,
+            low_water_mark: None,
+            gzip: false,
+        }
+    }
+
+    /// Sets the output format (array or NDJSON).
+    pub fn format(mut self, format: Format) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Nests the array under `field`, i.e. emits `{"<field>":[...]}`. Ignored for
+    /// `Format::JsonLines`, which has no enclosing object.
+    pub fn object_field(mut self, field: &'static str) -> Self {
+        self.field = Some(field);
+        self
+    }
+
+    /// Caps the amount of serialized-but-unsent data in flight, in bytes. Once that many bytes
+    /// are buffered ahead of the consumer, the producer suspends until the consumer has drained it
+    /// back down to the low water mark, so memory use stays flat regardless of how eager serde is
+    /// relative to the socket. Must be greater than zero, or the first write would never have
+    /// room to land and the producer would hang forever.
+    pub fn high_water_mark(mut self, bytes: usize) -> Self {
+        assert!(bytes > 0, "high_water_mark must be greater than zero");
+        self.high_water_mark = bytes;
+        self
+    }
+
+    /// Sets the low water mark the producer waits for before resuming once it has hit the high
+    /// water mark. Defaults to half of `high_water_mark`. Must be less than `high_water_mark`.
+    pub fn low_water_mark(mut self, bytes: usize) -> Self {
+        self.low_water_mark = Some(bytes);
+        self
+    }
+
+    /// Gzip-compresses the outgoing bytes as they're produced, without ever holding the full
+    /// payload in memory. Pair with [`JsonStreamBuilder::content_encoding`] to set the matching
+    /// `Content-Encoding` header.
+    pub fn gzip(mut self, enabled: bool) -> Self {
+        self.gzip = enabled;
+        self
+    }
+
+    /// The `Content-Encoding` header value for the current configuration, if any.
+    pub fn content_encoding(&self) -> Option<&'static str> {
+        self.gzip.then_some("gzip")
+    }
+
+    /// Consumes the builder, streaming JSON built from `items`.
+    pub fn build<S, T>(self, items: S) -> Body
+    where
+        S: Stream<Item = T> + Send + 'static,
+        T: Serialize + Send + 'static,
+    {
+        let low_water_mark = self.low_water_mark.unwrap_or(self.high_water_mark / 2);
+        assert!(
+            low_water_mark < self.high_water_mark,
+            "low_water_mark must be less than high_water_mark"
+        );
+
+        stream_json(
+            items,
+            self.field,
+            self.format,
+            self.high_water_mark,
+            low_water_mark,
+            self.gzip,
+        )
+    }
+}
+
+impl Default for JsonStreamBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Streams `items` as JSON in the given `format` (a JSON array or NDJSON), writing one element at
+/// a time with `serde_json::to_writer` so that neither the full `Vec` nor the full JSON string is
+/// ever materialized. The resulting `Body` can be handed straight to a reqwest request, e.g. to
+/// pipe a database cursor or a paginated API into a single large JSON upload with O(1) client
+/// memory. For more control (object wrapping, high water mark, gzip) use [`JsonStreamBuilder`].
+pub fn stream_json_array<S, T>(items: S, format: Format) -> Body
+where
+    S: Stream<Item = T> + Send + 'static,
+    T: Serialize + Send + 'static,
+{
+    JsonStreamBuilder::new().format(format).build(items)
+}
+
+/// Same as [`stream_json_array`] with `Format::Array`, but nests the array under a single object
+/// field, i.e. emits `{"<field>":[...]}`. This keeps payload shapes like the old
+/// `Foos { foos: Vec<Foo> }` struct working without ever materializing the array itself.
+pub fn stream_json_object<S, T>(field: &'static str, items: S) -> Body
+where
+    S: Stream<Item = T> + Send + 'static,
+    T: Serialize + Send + 'static,
+{
+    JsonStreamBuilder::new().object_field(field).build(items)
+}
+
+// Wraps the `ReaderStream` over the duplex read half (optionally gzip-compressed) so that, once
+// it runs dry, we also check whether the producer thread reported a terminal error (a failed
+// serialization, or a broken-pipe write once the consumer disappeared) instead of treating a dry
+// channel as always meaning a clean end of stream.
+struct ErrorAwareStream<R> {
+    inner: ReaderStream<R>,
+    error: oneshot::Receiver<io::Error>,
+    errored: bool,
+}
+
+impl<R> ErrorAwareStream<R> {
+    fn new(inner: ReaderStream<R>, error: oneshot::Receiver<io::Error>) -> Self {
+        Self {
+            inner,
+            error,
+            errored: false,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> Stream for ErrorAwareStream<R> {
+    type Item = io::Result<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.errored {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(&mut self.inner).poll_next(cx) {
+            Poll::Ready(None) => match Pin::new(&mut self.error).poll(cx) {
+                Poll::Ready(Ok(e)) => {
+                    self.errored = true;
+                    Poll::Ready(Some(Err(e)))
+                }
+                _ => Poll::Ready(None),
+            },
+            other => other,
+        }
+    }
+}
+
+fn stream_json<S, T>(
+    items: S,
+    field: Option<&'static str>,
+    format: Format,
+    high_water_mark: usize,
+    low_water_mark: usize,
+    gzip: bool,
+) -> Body
+where
+    S: Stream<Item = T> + Send + 'static,
+    T: Serialize + Send + 'static,
+{
+    Body::wrap_stream(build_stream(
+        items,
+        field,
+        format,
+        high_water_mark,
+        low_water_mark,
+        gzip,
+    ))
+}
+
+// split out from `stream_json` so tests can drive the raw Stream<Item = io::Result<Bytes>>
+// directly, without needing a live HTTP round trip just to observe what it yields
+fn build_stream<S, T>(
+    items: S,
+    field: Option<&'static str>,
+    format: Format,
+    high_water_mark: usize,
+    low_water_mark: usize,
+    gzip: bool,
+) -> Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>
+where
+    S: Stream<Item = T> + Send + 'static,
+    T: Serialize + Send + 'static,
+{
+    // the duplex channel's own capacity is a hard physical cap on in-flight bytes; the watermark
+    // adds the hysteresis on top of it, so the producer only resumes once the consumer has
+    // drained back down to `low_water_mark`, not the instant a single byte frees up
+    let (write_half, read_half) = tokio::io::duplex(high_water_mark);
+    let (error_tx, error_rx) = oneshot::channel();
+    let watermark = Arc::new(Watermark::new(high_water_mark, low_water_mark));
+    let writer_watermark = Arc::clone(&watermark);
+
+    tokio::task::spawn_blocking(move || {
+        let handle = Handle::current();
+        let mut writer = DuplexWriter::new(write_half, handle.clone(), writer_watermark);
+
+        let result: io::Result<()> = (|| {
+            match format {
+                Format::Array => {
+                    if let Some(field) = field {
+                        write!(writer, "{{\"{field}\":[")?;
+                    } else {
+                        writer.write_all(b"[")?;
+                    }
+
+                    // serde_json::to_writer is synchronous, so the stream itself is driven from
+                    // this blocking thread too, pulling one item at a time via Handle::block_on
+                    futures::pin_mut!(items);
+                    let mut first = true;
+                    while let Some(item) = handle.block_on(items.next()) {
+                        if !first {
+                            writer.write_all(b",")?;
+                        }
+                        first = false;
+                        serde_json::to_writer(&mut writer, &item).map_err(io::Error::other)?;
+                    }
+
+                    if field.is_some() {
+                        writer.write_all(b"]}")?;
+                    } else {
+                        writer.write_all(b"]")?;
+                    }
+                }
+                Format::JsonLines => {
+                    futures::pin_mut!(items);
+                    while let Some(item) = handle.block_on(items.next()) {
+                        serde_json::to_writer(&mut writer, &item).map_err(io::Error::other)?;
+                        writer.write_all(b"\n")?;
+                    }
+                }
+            }
+            Ok(())
+        })();
+
+        // if the consumer is already gone, nobody is waiting on error_rx and send() fails; that's
+        // fine, there's nothing left to report the error to
+        if let Err(e) = result {
+            let _ = error_tx.send(e);
+        }
+    });
+
+    // the read half becomes a proper async Stream<Item = Result<Bytes, io::Error>>: poll_next
+    // registers the waker and returns Poll::Pending when there's nothing to read yet, instead of
+    // busy-polling a blocking read. It's wrapped in WatermarkReader right on top of the duplex
+    // read half, so hysteresis tracks bytes actually freed from the duplex channel regardless of
+    // any further transform (e.g. gzip) downstream of it.
+    let read_half = WatermarkReader {
+        inner: read_half,
+        watermark,
+    };
+
+    if gzip {
+        // compresses incrementally as bytes come off the duplex channel, so the gzip stream
+        // never holds more than its own internal buffer of the payload either
+        let compressed = GzipEncoder::new(BufReader::new(read_half));
+        Box::pin(ErrorAwareStream::new(
+            ReaderStream::new(compressed),
+            error_rx,
+        ))
+    } else {
+        Box::pin(ErrorAwareStream::new(ReaderStream::new(read_half), error_rx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Serializes fine for `Ok`, fails for `Boom` -- lets us drive the producer into the error
+    // path deterministically.
+    enum MaybeBoom {
+        Ok(u32),
+        Boom,
+    }
+
+    impl Serialize for MaybeBoom {
+        fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: serde::Serializer,
+        {
+            match self {
+                MaybeBoom::Ok(n) => serializer.serialize_u32(*n),
+                MaybeBoom::Boom => Err(serde::ser::Error::custom("boom")),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn serialization_error_surfaces_as_a_stream_err() {
+        let items = futures::stream::iter(vec![
+            MaybeBoom::Ok(1),
+            MaybeBoom::Ok(2),
+            MaybeBoom::Boom,
+        ]);
+        let mut stream = build_stream(
+            items,
+            None,
+            Format::Array,
+            DEFAULT_HIGH_WATER_MARK,
+            DEFAULT_HIGH_WATER_MARK / 2,
+            false,
+        );
+
+        let mut saw_error = false;
+        while let Some(chunk) = stream.next().await {
+            if chunk.is_err() {
+                saw_error = true;
+                break;
+            }
+        }
+
+        assert!(
+            saw_error,
+            "expected the body stream to yield an Err once serialization failed, not hang or end cleanly"
+        );
+    }
+
+    // drains a Stream<Item = io::Result<Bytes>> fully, panicking on the first Err -- the happy
+    // path tests only care about the collected bytes
+    async fn collect_ok(
+        mut stream: Pin<Box<dyn Stream<Item = io::Result<Bytes>> + Send>>,
+    ) -> Vec<u8> {
+        let mut out = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            out.extend_from_slice(&chunk.expect("stream yielded an unexpected Err"));
+        }
+        out
+    }
+
+    #[derive(Serialize)]
+    struct Item {
+        id: u32,
+    }
+
+    #[tokio::test]
+    async fn array_format_serializes_items_in_order() {
+        let items = futures::stream::iter(vec![Item { id: 1 }, Item { id: 2 }, Item { id: 3 }]);
+        let stream = build_stream(
+            items,
+            None,
+            Format::Array,
+            DEFAULT_HIGH_WATER_MARK,
+            DEFAULT_HIGH_WATER_MARK / 2,
+            false,
+        );
+
+        let bytes = collect_ok(stream).await;
+        assert_eq!(bytes, br#"[{"id":1},{"id":2},{"id":3}]"#);
+    }
+
+    #[tokio::test]
+    async fn array_format_nested_under_object_field() {
+        let items = futures::stream::iter(vec![Item { id: 1 }, Item { id: 2 }]);
+        let stream = build_stream(
+            items,
+            Some("foos"),
+            Format::Array,
+            DEFAULT_HIGH_WATER_MARK,
+            DEFAULT_HIGH_WATER_MARK / 2,
+            false,
+        );
+
+        let bytes = collect_ok(stream).await;
+        assert_eq!(bytes, br#"{"foos":[{"id":1},{"id":2}]}"#);
+    }
+
+    #[tokio::test]
+    async fn array_format_empty_stream() {
+        let items = futures::stream::iter(Vec::<Item>::new());
+        let stream = build_stream(
+            items,
+            None,
+            Format::Array,
+            DEFAULT_HIGH_WATER_MARK,
+            DEFAULT_HIGH_WATER_MARK / 2,
+            false,
+        );
+
+        let bytes = collect_ok(stream).await;
+        assert_eq!(bytes, b"[]");
+    }
+
+    #[tokio::test]
+    async fn array_format_empty_stream_nested_under_object_field() {
+        let items = futures::stream::iter(Vec::<Item>::new());
+        let stream = build_stream(
+            items,
+            Some("foos"),
+            Format::Array,
+            DEFAULT_HIGH_WATER_MARK,
+            DEFAULT_HIGH_WATER_MARK / 2,
+            false,
+        );
+
+        let bytes = collect_ok(stream).await;
+        assert_eq!(bytes, br#"{"foos":[]}"#);
+    }
+
+    #[tokio::test]
+    async fn json_lines_format_emits_one_value_per_line() {
+        let items = futures::stream::iter(vec![Item { id: 1 }, Item { id: 2 }, Item { id: 3 }]);
+        let stream = build_stream(
+            items,
+            None,
+            Format::JsonLines,
+            DEFAULT_HIGH_WATER_MARK,
+            DEFAULT_HIGH_WATER_MARK / 2,
+            false,
+        );
+
+        let bytes = collect_ok(stream).await;
+        assert_eq!(bytes, b"{\"id\":1}\n{\"id\":2}\n{\"id\":3}\n");
+    }
+
+    #[tokio::test]
+    async fn json_lines_format_ignores_object_field() {
+        let items = futures::stream::iter(vec![Item { id: 1 }, Item { id: 2 }]);
+        let stream = build_stream(
+            items,
+            Some("foos"),
+            Format::JsonLines,
+            DEFAULT_HIGH_WATER_MARK,
+            DEFAULT_HIGH_WATER_MARK / 2,
+            false,
+        );
+
+        let bytes = collect_ok(stream).await;
+        assert_eq!(bytes, b"{\"id\":1}\n{\"id\":2}\n");
+    }
+
+    // a 128-byte high water mark against a few hundred small items forces the producer through
+    // several suspend/drain cycles (each item is ~11 bytes serialized, so well under 128 bytes
+    // lets multiple items accumulate before a suspend, and the low water mark forces a real drain
+    // rather than resuming the instant a single byte frees up) -- regression guard for the
+    // pre-fix bug where `high_water_mark` was just the duplex capacity with no hysteresis at all
+    #[tokio::test]
+    async fn hysteresis_survives_multiple_drain_cycles() {
+        let n = 500u32;
+        let items = futures::stream::iter((0..n).map(|id| Item { id }));
+        let stream = build_stream(items, None, Format::Array, 128, 32, false);
+
+        let bytes = collect_ok(stream).await;
+
+        let expected = {
+            let mut expected = b"[".to_vec();
+            for id in 0..n {
+                if id > 0 {
+                    expected.push(b',');
+                }
+                expected.extend_from_slice(format!("{{\"id\":{id}}}").as_bytes());
+            }
+            expected.push(b']');
+            expected
+        };
+        assert_eq!(bytes, expected);
+    }
+
+    #[tokio::test]
+    async fn gzip_output_decodes_to_the_same_json_as_uncompressed() {
+        use std::io::Read;
+
+        let items = futures::stream::iter(vec![Item { id: 1 }, Item { id: 2 }, Item { id: 3 }]);
+        let compressed = collect_ok(build_stream(
+            items,
+            None,
+            Format::Array,
+            DEFAULT_HIGH_WATER_MARK,
+            DEFAULT_HIGH_WATER_MARK / 2,
+            true,
+        ))
+        .await;
+
+        let mut decoded = Vec::new();
+        flate2::read::GzDecoder::new(&compressed[..])
+            .read_to_end(&mut decoded)
+            .expect("gzip output should decode cleanly");
+
+        assert_eq!(decoded, br#"[{"id":1},{"id":2},{"id":3}]"#);
+    }
+}